@@ -1,25 +1,194 @@
 #![doc = include_str!("../README.md")]
 
-use std::collections::hash_map::DefaultHasher;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fs;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+/// A `Hasher` that feeds every byte it's given into a SHA-256 digest instead of folding
+/// them into a 64-bit value. `finish()` is unused (and not meaningful) here; call
+/// `finalize_hex` once hashing is done to get the actual digest.
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        0
+    }
+}
+
+impl Sha256Hasher {
+    fn finalize_hex(self) -> String {
+        format!("{:x}", self.0.finalize())
+    }
+}
 
-pub fn hash<T: Hash>(t: &T) -> u64 {
-    let mut hasher = DefaultHasher::new();
+/// Hashes `t` to a stable SHA-256 hex digest, used to derive cache file names. Unlike
+/// `DefaultHasher`, this is stable across Rust releases and wide enough that collisions
+/// are not a practical concern.
+pub fn hash<T: Hash>(t: &T) -> String {
+    let mut hasher = Sha256Hasher(Sha256::new());
     t.hash(&mut hasher);
-    hasher.finish()
+    hasher.finalize_hex()
+}
+
+/// Size and modification time of the binary a cached command invokes, captured so a cache
+/// entry can be invalidated the moment that binary is upgraded.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BinaryMetadata {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified_secs: u64,
+}
+
+/// The full result of running a command, as captured the moment it was executed.
+///
+/// This is what gets persisted to the cache file (as JSON, optionally zstd-compressed,
+/// behind a small header; see `encode_cache_entry`), so that a cache hit
+/// can reproduce stdout, stderr and the exit code exactly as if the command had
+/// just run. `command`/`cwd`/`env` mirror the `CacheKey` that produced this entry's file
+/// name, so a read can confirm the hash didn't collide before serving the hit.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CachedResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub binary_metadata: Option<BinaryMetadata>,
+    pub command: String,
+    pub cwd: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+}
+
+impl CachedResult {
+    fn from_output(
+        output: &std::process::Output,
+        binary_metadata: Option<BinaryMetadata>,
+        key: &CacheKey,
+    ) -> Self {
+        CachedResult {
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            exit_code: output.status.code().unwrap_or(-1),
+            binary_metadata,
+            command: key.command.to_string(),
+            cwd: key.cwd.clone(),
+            env: key.env.clone(),
+        }
+    }
+
+    /// Whether this entry was produced for the same cache key as `key`. A mismatch means
+    /// the cache file name collided with a different command/cwd/env combination.
+    fn matches_key(&self, key: &CacheKey) -> bool {
+        self.command == key.command && self.cwd == key.cwd && self.env == key.env
+    }
+}
+
+/// Resolves the leading word of `command` (the binary it invokes) to an absolute path and
+/// returns its current size and modification time, so a cache entry can later detect that
+/// the binary has been upgraded.
+fn resolve_binary_metadata(command: &str) -> Option<BinaryMetadata> {
+    let leading_word = command.split_whitespace().next()?;
+    let path = resolve_binary_path(leading_word)?;
+    let metadata = fs::metadata(&path).ok()?;
+    let modified_secs = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(BinaryMetadata {
+        path,
+        size: metadata.len(),
+        modified_secs,
+    })
+}
+
+/// Resolves `leading_word` to an absolute path: against the current directory if it
+/// already contains a path separator, otherwise by searching `PATH`, the same way a shell
+/// would.
+fn resolve_binary_path(leading_word: &str) -> Option<PathBuf> {
+    let candidate = PathBuf::from(leading_word);
+    if leading_word.contains(std::path::MAIN_SEPARATOR) {
+        return candidate
+            .is_file()
+            .then(|| std::path::absolute(&candidate).ok())
+            .flatten();
+    }
+
+    std::env::var_os("PATH").and_then(|path_var| {
+        std::env::split_paths(&path_var).find_map(|dir| {
+            let full_path = dir.join(leading_word);
+            full_path.is_file().then_some(full_path)
+        })
+    })
+}
+
+/// The identity of a cache entry. Two calls with the same `command` but a different
+/// working directory or environment share one entry unless they opt into scoping by
+/// `cwd`/`env`, which folds those values into the hash alongside `command`.
+#[derive(Debug, Hash)]
+pub struct CacheKey<'a> {
+    pub command: &'a str,
+    pub cwd: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+}
+
+impl<'a> CacheKey<'a> {
+    /// Builds a cache key for `command`, optionally scoped by the current working
+    /// directory and by the current value of each name in `env_vars`. Unset env vars are
+    /// skipped; the rest are sorted by name so argument order doesn't affect the key.
+    pub fn new(command: &'a str, cwd: Option<PathBuf>, env_vars: &[String]) -> Self {
+        let mut env: Vec<(String, String)> = env_vars
+            .iter()
+            .filter_map(|name| std::env::var(name).ok().map(|value| (name.clone(), value)))
+            .collect();
+        env.sort();
+        CacheKey { command, cwd, env }
+    }
+}
+
+/// Tunable knobs for `cache_aware_execute_command`. Bundled into a struct because the
+/// TTL/stale/scope/compress settings had grown into an unwieldy flat argument list.
+pub struct ExecuteOptions<'a> {
+    pub ttl: u64,
+    pub does_force_renew_cache: bool,
+    pub stale: Option<u64>,
+    pub scope_cwd: bool,
+    pub scope_env_vars: &'a [String],
+    pub compress: bool,
+    /// Forwarded to the background `--stale` refresh child so its own opportunistic `gc()`
+    /// enforces the same limits as the foreground invocation.
+    pub max_size: Option<u64>,
+    pub max_entries: Option<u64>,
 }
 
 /// This function executes the given command and caches the result if the cache is expired or not exists.
 /// If the cache is not expired, it just returns the cached result without execution command.
+///
+/// When `options.stale` is set and the cache entry is older than that threshold (but still
+/// within `options.ttl`), the cached result is returned immediately and a detached background
+/// process is spawned to refresh the cache, so the caller never pays the full command latency.
+///
+/// A cache entry is also treated as expired, regardless of `options.ttl`, if the binary the
+/// command invokes has been upgraded since the entry was written (see `BinaryMetadata`).
 pub fn cache_aware_execute_command(
     command: &str,
-    ttl: u64,
-    cache_file: &PathBuf,
-    does_force_renew_cache: bool,
-) -> String {
+    cache_file: &Path,
+    options: &ExecuteOptions,
+) -> CachedResult {
+    let current_binary_metadata = resolve_binary_metadata(command);
+    let current_key = CacheKey::new(
+        command,
+        resolve_scope_cwd(options.scope_cwd),
+        options.scope_env_vars,
+    );
+
     if cache_file.exists() && cache_file.is_file() {
         let metadata = fs::metadata(cache_file).expect("Unable to read metadata of cache file");
         let created = metadata
@@ -29,31 +198,299 @@ pub fn cache_aware_execute_command(
         let elapsed = now
             .duration_since(created)
             .expect("Unable to calculate elapsed time");
-        if elapsed.as_secs() < ttl && !does_force_renew_cache {
-            return String::from_utf8_lossy(
-                &fs::read(cache_file).expect("Unable to read cache file"),
-            )
-            .to_string();
-        } else {
-            clean_cache(cache_file);
+
+        if elapsed.as_secs() < options.ttl && !options.does_force_renew_cache {
+            let raw = fs::read(cache_file).expect("Unable to read cache file");
+
+            if let Some(cached) = decode_cache_entry(&raw) {
+                if cached.binary_metadata == current_binary_metadata && cached.matches_key(&current_key) {
+                    touch_last_used(cache_file);
+                    if let Some(stale) = options.stale {
+                        if elapsed.as_secs() >= stale {
+                            spawn_background_refresh(command, cache_file, options);
+                        }
+                    }
+                    return cached;
+                }
+            }
         }
+
+        clean_cache(cache_file);
     }
 
     let output = Command::new("sh")
         .args(["-c", command])
         .output()
         .expect("failed to execute process");
-    fs::write(cache_file, &output.stdout).expect("Unable to write cache file");
-    String::from_utf8_lossy(&output.stdout).to_string()
+    let result = CachedResult::from_output(&output, current_binary_metadata, &current_key);
+    write_cache_atomically(cache_file, &result, options.compress);
+    result
+}
+
+/// Resolves the current working directory when `scope_cwd` is set, mirroring how the CLI
+/// builds the `CacheKey` that determined `cache_file`'s name.
+fn resolve_scope_cwd(scope_cwd: bool) -> Option<PathBuf> {
+    scope_cwd.then(|| std::env::current_dir().expect("Unable to read current directory"))
+}
+
+/// Removes its lock file when dropped, including on unwind, unless `defuse`d first. Guards
+/// the window between acquiring a lockfile and handing ownership of it off elsewhere (e.g.
+/// to the spawned child's own guard in `warm_cache`), so an early return or a panic in
+/// between doesn't leave the lock behind forever and silently disable all future `--stale`
+/// background refreshes for that entry.
+struct LockGuard {
+    lock_file: PathBuf,
+    armed: bool,
+}
+
+impl LockGuard {
+    fn new(lock_file: PathBuf) -> Self {
+        LockGuard {
+            lock_file,
+            armed: true,
+        }
+    }
+
+    /// Leaves the lock file in place instead of removing it on drop, because ownership of
+    /// cleaning it up has been handed off (e.g. the refresh child was spawned successfully
+    /// and will remove it itself via its own `LockGuard` in `warm_cache`).
+    fn defuse(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            let _ = fs::remove_file(&self.lock_file);
+        }
+    }
+}
+
+/// Runs `command` and writes its result to `cache_file` without returning or printing
+/// anything. Used both for pre-warming the cache (e.g. from cron) and as the background
+/// refresh path for `--stale`.
+pub fn warm_cache(
+    command: &str,
+    cache_file: &Path,
+    compress: bool,
+    scope_cwd: bool,
+    scope_env_vars: &[String],
+) {
+    let _lock_guard = LockGuard::new(lock_file_path(cache_file));
+    let binary_metadata = resolve_binary_metadata(command);
+    let key = CacheKey::new(command, resolve_scope_cwd(scope_cwd), scope_env_vars);
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .expect("failed to execute process");
+    let result = CachedResult::from_output(&output, binary_metadata, &key);
+    write_cache_atomically(cache_file, &result, compress);
+}
+
+/// Bumped whenever the on-disk cache format changes. A cache file whose leading version
+/// byte doesn't match is treated as if it didn't exist, so old caches never get misparsed
+/// by a newer build.
+const CURRENT_VERSION: u8 = 1;
+
+/// Writes `result` to `cache_file` atomically: it's encoded (see `encode_cache_entry`) to a
+/// temp file next to `cache_file` and then renamed into place, so concurrent readers never
+/// observe a half-written entry.
+fn write_cache_atomically(cache_file: &Path, result: &CachedResult, compress: bool) {
+    let encoded = encode_cache_entry(result, compress);
+    let tmp_file = tmp_file_path(cache_file);
+    fs::write(&tmp_file, encoded).expect("Unable to write cache file");
+    fs::rename(&tmp_file, cache_file).expect("Unable to rename cache file into place");
+}
+
+/// Serializes `result` to JSON, optionally zstd-compressing it, and prefixes the whole
+/// payload with a version byte and a compression flag byte.
+fn encode_cache_entry(result: &CachedResult, compress: bool) -> Vec<u8> {
+    let json = serde_json::to_vec(result).expect("Unable to serialize command result");
+    let payload = if compress {
+        zstd::stream::encode_all(&json[..], 0).expect("Unable to compress cache entry")
+    } else {
+        json
+    };
+
+    let mut encoded = Vec::with_capacity(payload.len() + 2);
+    encoded.push(CURRENT_VERSION);
+    encoded.push(compress as u8);
+    encoded.extend_from_slice(&payload);
+    encoded
+}
+
+/// The inverse of `encode_cache_entry`. Returns `None` if the version byte doesn't match
+/// `CURRENT_VERSION` or the entry is otherwise malformed, so the caller can treat it as a
+/// miss and re-execute instead of crashing on a stale on-disk format.
+fn decode_cache_entry(raw: &[u8]) -> Option<CachedResult> {
+    let (&version, rest) = raw.split_first()?;
+    if version != CURRENT_VERSION {
+        return None;
+    }
+    let (&compressed, payload) = rest.split_first()?;
+
+    let json = if compressed == 1 {
+        zstd::stream::decode_all(payload).ok()?
+    } else {
+        payload.to_vec()
+    };
+    serde_json::from_slice(&json).ok()
+}
+
+fn tmp_file_path(cache_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.tmp", cache_file.display()))
+}
+
+fn lock_file_path(cache_file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.lock", cache_file.display()))
+}
+
+/// Spawns a detached child process (this same binary, run with `--warm`) that re-executes
+/// `command` and refreshes `cache_file` in the background. Guarded by a lockfile keyed on
+/// the cache file's path so only one refresh runs at a time.
+///
+/// `options.scope_cwd`/`options.scope_env_vars` are forwarded as `--cwd`/`--env` so the child
+/// recomputes the exact same cache key as the parent (it inherits the parent's working
+/// directory and environment, so no actual values need to be passed). `options.max_size`/
+/// `options.max_entries` are forwarded as `--max-size`/`--max-entries` so the child's own
+/// opportunistic `gc()` (see `main.rs`) enforces the same limits the parent was configured
+/// with, instead of silently no-op'ing.
+fn spawn_background_refresh(command: &str, cache_file: &Path, options: &ExecuteOptions) {
+    let lock_file = lock_file_path(cache_file);
+    let acquired_lock = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_file)
+        .is_ok();
+    if !acquired_lock {
+        // A refresh is already in flight for this entry.
+        return;
+    }
+    let lock_guard = LockGuard::new(lock_file);
+
+    let current_exe = match std::env::current_exe() {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let mut refresh = Command::new(current_exe);
+    refresh
+        .arg("--warm")
+        .arg("--ttl")
+        .arg(options.ttl.to_string());
+    if options.scope_cwd {
+        refresh.arg("--cwd");
+    }
+    for env_var in options.scope_env_vars {
+        refresh.arg("--env").arg(env_var);
+    }
+    if options.compress {
+        refresh.arg("--compress");
+    }
+    if let Some(max_size) = options.max_size {
+        refresh.arg("--max-size").arg(max_size.to_string());
+    }
+    if let Some(max_entries) = options.max_entries {
+        refresh.arg("--max-entries").arg(max_entries.to_string());
+    }
+    refresh
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    if refresh.spawn().is_ok() {
+        // The child now owns the lock file and will remove it itself (via its own
+        // `LockGuard` in `warm_cache`) once it finishes; don't race it by also removing it
+        // here when `lock_guard` drops.
+        lock_guard.defuse();
+    }
 }
 
 /// This function removes the cache file.
-pub fn clean_cache(cache_file: &PathBuf) {
+pub fn clean_cache(cache_file: &Path) {
     fs::remove_file(cache_file).expect("Unable to remove cache file");
 }
 
+/// Records that `cache_file` was just served as a hit, by bumping its access time. Doesn't
+/// touch the modification/birth time the TTL check relies on.
+fn touch_last_used(cache_file: &Path) {
+    if let Ok(file) = fs::File::open(cache_file) {
+        let times = fs::FileTimes::new().set_accessed(std::time::SystemTime::now());
+        let _ = file.set_times(times);
+    }
+}
+
+/// A cache entry discovered under the cache root, along with the bookkeeping GC needs:
+/// its size and when it was last used (served as a hit).
+struct CacheEntryStats {
+    path: PathBuf,
+    size: u64,
+    last_used: std::time::SystemTime,
+}
+
+/// Lists the cache entries directly under `cache_root_dir`, skipping the `.tmp` and
+/// `.lock` files the atomic-write/refresh machinery leaves behind transiently.
+fn list_cache_entries(cache_root_dir: &Path) -> Vec<CacheEntryStats> {
+    let Ok(dir) = fs::read_dir(cache_root_dir) else {
+        return Vec::new();
+    };
+
+    dir.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path.extension() != Some(std::ffi::OsStr::new("tmp"))
+                && path.extension() != Some(std::ffi::OsStr::new("lock"))
+        })
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            let last_used = metadata.accessed().ok()?;
+            Some(CacheEntryStats {
+                path,
+                size: metadata.len(),
+                last_used,
+            })
+        })
+        .collect()
+}
+
+/// Evicts the least-recently-used cache entries under `cache_root_dir` until the total
+/// size and entry count are at or below `max_size`/`max_entries`. Either limit may be
+/// omitted to leave that dimension unbounded. Returns the number of entries evicted.
+pub fn gc(cache_root_dir: &Path, max_size: Option<u64>, max_entries: Option<u64>) -> usize {
+    if max_size.is_none() && max_entries.is_none() {
+        return 0;
+    }
+
+    let mut entries = list_cache_entries(cache_root_dir);
+    entries.sort_by_key(|entry| entry.last_used);
+
+    let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut total_count = entries.len() as u64;
+    let mut evicted = 0;
+
+    for entry in entries {
+        let over_size = max_size.is_some_and(|max| total_size > max);
+        let over_count = max_entries.is_some_and(|max| total_count > max);
+        if !over_size && !over_count {
+            break;
+        }
+
+        if fs::remove_file(&entry.path).is_ok() {
+            total_size = total_size.saturating_sub(entry.size);
+            total_count = total_count.saturating_sub(1);
+            evicted += 1;
+        }
+    }
+
+    evicted
+}
+
 #[cfg(test)]
 mod tests {
+    use super::CachedResult;
     use std::{
         fs,
         thread::sleep,
@@ -87,20 +524,51 @@ mod tests {
         }
     }
 
+    fn write_cache(cache_file: &std::path::Path, result: &CachedResult) {
+        let encoded = super::encode_cache_entry(result, false);
+        fs::write(cache_file, encoded).expect("Unable to write cache file");
+    }
+
+    /// `ExecuteOptions` with every knob at its default, for tests that only care about one
+    /// non-default field (e.g. `ExecuteOptions { stale, ..default_options(ttl) }`).
+    fn default_options(ttl: u64) -> super::ExecuteOptions<'static> {
+        super::ExecuteOptions {
+            ttl,
+            does_force_renew_cache: false,
+            stale: None,
+            scope_cwd: false,
+            scope_env_vars: &[],
+            compress: false,
+            max_size: None,
+            max_entries: None,
+        }
+    }
+
     #[test]
     fn just_return_cache_without_execution_if_cache_is_exists() {
         let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
 
         let cache_file = ctx.cache_root_path.join("test_cache");
-        let _ = std::fs::write(&cache_file, "not hello").expect("Unable to write cache file");
-
         let command = "sleep 10 && echo 'hello'";
+        let key = super::CacheKey::new(command, None, &[]);
+        let cached = CachedResult {
+            stdout: "not hello".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+            binary_metadata: super::resolve_binary_metadata(command),
+            command: key.command.to_string(),
+            cwd: key.cwd.clone(),
+            env: key.env.clone(),
+        };
+        write_cache(&cache_file, &cached);
+
         let ttl = 60;
 
         let start = Instant::now(); // Start timing
 
-        let result = super::cache_aware_execute_command(command, ttl, &cache_file, false);
-        assert_eq!(result, "not hello");
+        let result =
+            super::cache_aware_execute_command(command, &cache_file, &default_options(ttl));
+        assert_eq!(result, cached);
 
         let duration = start.elapsed(); // Measure how long it took
         assert!(
@@ -121,8 +589,11 @@ mod tests {
 
         let start = Instant::now(); // Start timing
 
-        let result = super::cache_aware_execute_command(command, ttl, &cache_file, false);
-        assert_eq!(result, "hello\n");
+        let result =
+            super::cache_aware_execute_command(command, &cache_file, &default_options(ttl));
+        assert_eq!(result.stdout, "hello\n");
+        assert_eq!(result.stderr, "");
+        assert_eq!(result.exit_code, 0);
 
         let duration = start.elapsed(); // Measure how long it took
         assert!(
@@ -137,7 +608,16 @@ mod tests {
         let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
 
         let cache_file = ctx.cache_root_path.join("test_cache");
-        let _ = std::fs::write(&cache_file, "not hello").expect("Unable to write cache file");
+        let cached = CachedResult {
+            stdout: "not hello".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+            binary_metadata: None,
+            command: "not hello".to_string(),
+            cwd: None,
+            env: Vec::new(),
+        };
+        write_cache(&cache_file, &cached);
 
         let command = "sleep 2 && echo 'hello'";
         let ttl = 1;
@@ -145,8 +625,9 @@ mod tests {
         sleep(Duration::from_secs(1));
         let start = Instant::now(); // Start timing
 
-        let result = super::cache_aware_execute_command(command, ttl, &cache_file, false);
-        assert_eq!(result, "hello\n");
+        let result =
+            super::cache_aware_execute_command(command, &cache_file, &default_options(ttl));
+        assert_eq!(result.stdout, "hello\n");
 
         let duration = start.elapsed(); // Measure how long it took
         assert!(
@@ -164,7 +645,16 @@ mod tests {
         let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
 
         let cache_file = ctx.cache_root_path.join("test_cache");
-        let _ = std::fs::write(&cache_file, "not hello").expect("Unable to write cache file");
+        let cached = CachedResult {
+            stdout: "not hello".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+            binary_metadata: None,
+            command: "not hello".to_string(),
+            cwd: None,
+            env: Vec::new(),
+        };
+        write_cache(&cache_file, &cached);
         let old_cache_file_created = fs::metadata(&cache_file)
             .expect("Unable to read metadata of cache file")
             .created()
@@ -175,7 +665,7 @@ mod tests {
 
         sleep(Duration::from_secs(2));
 
-        let _ = super::cache_aware_execute_command(command, ttl, &cache_file, false);
+        let _ = super::cache_aware_execute_command(command, &cache_file, &default_options(ttl));
 
         let renewed_cache_file_created = fs::metadata(&cache_file)
             .expect("Unable to read metadata of cache file")
@@ -193,13 +683,304 @@ mod tests {
         let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
 
         let cache_file = ctx.cache_root_path.join("test_cache");
-        let _ = std::fs::write(&cache_file, "not hello").expect("Unable to write cache file");
+        let cached = CachedResult {
+            stdout: "not hello".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+            binary_metadata: None,
+            command: "not hello".to_string(),
+            cwd: None,
+            env: Vec::new(),
+        };
+        write_cache(&cache_file, &cached);
 
         let command = "sleep 1 && echo 'hello'";
         let ttl = 60;
 
-        let result = super::cache_aware_execute_command(command, ttl, &cache_file, true);
+        let result = super::cache_aware_execute_command(
+            command,
+            &cache_file,
+            &super::ExecuteOptions {
+                does_force_renew_cache: true,
+                ..default_options(ttl)
+            },
+        );
+
+        assert_eq!(result.stdout, "hello\n", "Cache is not renewed: {:?}", result);
+    }
+
+    #[test]
+    fn captures_stderr_and_non_zero_exit_code() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        let command = "echo 'oops' 1>&2; exit 3";
+        let ttl = 60;
+
+        let result =
+            super::cache_aware_execute_command(command, &cache_file, &default_options(ttl));
+
+        assert_eq!(result.stdout, "");
+        assert_eq!(result.stderr, "oops\n");
+        assert_eq!(result.exit_code, 3);
+    }
+
+    #[test]
+    fn stale_cache_is_still_returned_immediately() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        let command = "echo 'hello'";
+        let key = super::CacheKey::new(command, None, &[]);
+        let cached = CachedResult {
+            stdout: "not hello".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+            binary_metadata: super::resolve_binary_metadata(command),
+            command: key.command.to_string(),
+            cwd: key.cwd.clone(),
+            env: key.env.clone(),
+        };
+        write_cache(&cache_file, &cached);
+
+        let ttl = 60;
+        let stale = Some(0);
+
+        let start = Instant::now();
+        let result = super::cache_aware_execute_command(
+            command,
+            &cache_file,
+            &super::ExecuteOptions {
+                stale,
+                ..default_options(ttl)
+            },
+        );
+        let duration = start.elapsed();
+
+        assert_eq!(result, cached);
+        assert!(
+            duration <= Duration::from_secs(1),
+            "Stale cache lookup should not block on the refresh: {:?}",
+            duration
+        );
+    }
+
+    #[test]
+    fn spawn_background_refresh_is_skipped_when_a_refresh_is_already_in_flight() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        let lock_file = super::lock_file_path(&cache_file);
+        fs::File::create_new(&lock_file).expect("Unable to create lock file");
+        let lock_created = fs::metadata(&lock_file)
+            .expect("Unable to read metadata of lock file")
+            .created()
+            .expect("Unable to read created date of lock file");
+
+        super::spawn_background_refresh("echo 'hello'", &cache_file, &default_options(60));
+
+        let lock_still_created = fs::metadata(&lock_file)
+            .expect("lock file should still be in place")
+            .created()
+            .expect("Unable to read created date of lock file");
+        assert_eq!(
+            lock_created, lock_still_created,
+            "the in-flight refresh's lock file should be left untouched, not replaced"
+        );
+    }
+
+    #[test]
+    fn lock_guard_removes_its_file_unless_defused() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let lock_file = ctx.cache_root_path.join("test_cache.lock");
+        fs::File::create_new(&lock_file).expect("Unable to create lock file");
+        drop(super::LockGuard::new(lock_file.clone()));
+        assert!(
+            !lock_file.exists(),
+            "dropping an armed guard should remove its lock file"
+        );
+
+        fs::File::create_new(&lock_file).expect("Unable to create lock file");
+        super::LockGuard::new(lock_file.clone()).defuse();
+        assert!(
+            lock_file.exists(),
+            "a defused guard must leave its lock file in place, e.g. once the spawned \
+             refresh child has taken ownership of removing it"
+        );
+    }
+
+    #[test]
+    fn warm_cache_populates_without_printing() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        super::warm_cache("echo 'hello'", &cache_file, false, false, &[]);
+        assert!(cache_file.exists(), "warm_cache should have written the cache file");
+
+        let result = super::cache_aware_execute_command(
+            "echo 'hello'",
+            &cache_file,
+            &default_options(60),
+        );
+        assert_eq!(result.stdout, "hello\n");
+    }
+
+    #[test]
+    fn cache_is_invalidated_when_binary_metadata_changed() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        let command = "echo 'hello'";
+        let mut stale_binary_metadata =
+            super::resolve_binary_metadata(command).expect("echo should resolve via PATH");
+        stale_binary_metadata.size += 1;
+        let key = super::CacheKey::new(command, None, &[]);
+        let cached = CachedResult {
+            stdout: "not hello".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+            binary_metadata: Some(stale_binary_metadata),
+            command: key.command.to_string(),
+            cwd: key.cwd.clone(),
+            env: key.env.clone(),
+        };
+        write_cache(&cache_file, &cached);
+
+        let ttl = 60;
+
+        let result =
+            super::cache_aware_execute_command(command, &cache_file, &default_options(ttl));
+
+        assert_eq!(result.stdout, "hello\n");
+    }
+
+    #[test]
+    fn cache_hit_is_rejected_when_stored_key_does_not_match() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        let command = "echo 'hello'";
+        let key = super::CacheKey::new(command, None, &[]);
+        let cached = CachedResult {
+            stdout: "not hello".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+            binary_metadata: super::resolve_binary_metadata(command),
+            command: "echo 'a different command'".to_string(),
+            cwd: key.cwd.clone(),
+            env: key.env.clone(),
+        };
+        write_cache(&cache_file, &cached);
+
+        let ttl = 60;
+
+        let result =
+            super::cache_aware_execute_command(command, &cache_file, &default_options(ttl));
+
+        assert_eq!(
+            result.stdout, "hello\n",
+            "A cache entry whose stored key doesn't match should be re-executed, guarding against hash collisions"
+        );
+    }
+
+    #[test]
+    fn cache_key_is_scoped_by_cwd_and_selected_env_vars() {
+        let command = "ls";
+
+        let unscoped_a = super::hash(&super::CacheKey::new(command, None, &[]));
+        let unscoped_b = super::hash(&super::CacheKey::new(command, None, &[]));
+        assert_eq!(unscoped_a, unscoped_b);
+
+        let scoped_by_cwd_a = super::hash(&super::CacheKey::new(
+            command,
+            Some(std::path::PathBuf::from("/tmp")),
+            &[],
+        ));
+        let scoped_by_cwd_b = super::hash(&super::CacheKey::new(
+            command,
+            Some(std::path::PathBuf::from("/var")),
+            &[],
+        ));
+        assert_ne!(scoped_by_cwd_a, scoped_by_cwd_b);
+        assert_ne!(unscoped_a, scoped_by_cwd_a);
+
+        std::env::set_var("FCLICACHE_TEST_ENV_VAR", "one");
+        let env_scoped_a =
+            super::hash(&super::CacheKey::new(command, None, &["FCLICACHE_TEST_ENV_VAR".to_string()]));
+        std::env::set_var("FCLICACHE_TEST_ENV_VAR", "two");
+        let env_scoped_b =
+            super::hash(&super::CacheKey::new(command, None, &["FCLICACHE_TEST_ENV_VAR".to_string()]));
+        std::env::remove_var("FCLICACHE_TEST_ENV_VAR");
+        assert_ne!(env_scoped_a, env_scoped_b);
+    }
+
+    #[test]
+    fn compressed_cache_entry_round_trips() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        let command = "echo 'hello'";
+        let ttl = 60;
+
+        let options = super::ExecuteOptions {
+            compress: true,
+            ..default_options(ttl)
+        };
+        let first = super::cache_aware_execute_command(command, &cache_file, &options);
+        assert_eq!(first.stdout, "hello\n");
+
+        let second = super::cache_aware_execute_command(command, &cache_file, &options);
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn cache_entry_with_mismatched_version_is_re_executed() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        fs::write(&cache_file, [0xFFu8, 0x00, b'x']).expect("Unable to write cache file");
+
+        let result = super::cache_aware_execute_command(
+            "echo 'hello'",
+            &cache_file,
+            &default_options(60),
+        );
+
+        assert_eq!(result.stdout, "hello\n");
+    }
+
+    #[test]
+    fn gc_evicts_least_recently_used_entries_down_to_max_entries() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let oldest = ctx.cache_root_path.join("oldest");
+        let newest = ctx.cache_root_path.join("newest");
+        super::warm_cache("echo 'old'", &oldest, false, false, &[]);
+        super::warm_cache("echo 'new'", &newest, false, false, &[]);
+
+        let old_time = std::time::SystemTime::now() - Duration::from_secs(60);
+        let file = fs::File::open(&oldest).expect("Unable to open cache file");
+        let times = fs::FileTimes::new().set_accessed(old_time);
+        file.set_times(times).expect("Unable to set access time");
+
+        let evicted = super::gc(&ctx.cache_root_path, None, Some(1));
+
+        assert_eq!(evicted, 1);
+        assert!(!oldest.exists());
+        assert!(newest.exists());
+    }
+
+    #[test]
+    fn gc_is_a_no_op_without_limits() {
+        let ctx = TestContext::new(&format!("{}{}", file!(), line!()));
+
+        let cache_file = ctx.cache_root_path.join("test_cache");
+        super::warm_cache("echo 'hello'", &cache_file, false, false, &[]);
+
+        let evicted = super::gc(&ctx.cache_root_path, None, None);
 
-        assert!(result == "hello\n", "Cache is not renewed: {:?}", result);
+        assert_eq!(evicted, 0);
+        assert!(cache_file.exists());
     }
 }
@@ -1,7 +1,7 @@
 //! This is a simple command line tool that caches the output of a command for a given TTL.
 
 use clap::Parser;
-use fclicache::{cache_aware_execute_command, hash};
+use fclicache::{cache_aware_execute_command, gc, hash, warm_cache, CacheKey, ExecuteOptions};
 use std::env;
 
 #[derive(Parser, Debug)]
@@ -15,17 +15,55 @@ struct Args {
     #[arg(short = 'c', long = "clean")]
     force_renew_cache: bool,
 
+    /// Serve a cache entry older than this many seconds (but still within `ttl`)
+    /// immediately, while refreshing it in the background.
+    #[arg(long)]
+    stale: Option<u64>,
+
+    /// Run the command and populate the cache without printing anything.
+    /// Useful for pre-warming the cache from cron, or as the background
+    /// refresh path for `--stale`.
+    #[arg(long)]
+    warm: bool,
+
+    /// Scope the cache entry to the current working directory, so the same
+    /// command run from two different directories is cached separately.
+    #[arg(long)]
+    cwd: bool,
+
+    /// Fold the current value of this environment variable into the cache key.
+    /// May be given multiple times.
+    #[arg(long = "env", value_name = "VAR")]
+    env_vars: Vec<String>,
+
+    /// Compress cache entries with zstd. Saves disk space for commands with large output.
+    #[arg(long)]
+    compress: bool,
+
+    /// Evict least-recently-used cache entries down to `--max-size`/`--max-entries`, then
+    /// exit without running a command.
+    #[arg(long)]
+    gc: bool,
+
+    /// Cap on the total size (in bytes) of the cache directory. Enforced by `--gc` and
+    /// opportunistically after each cache write.
+    #[arg(long)]
+    max_size: Option<u64>,
+
+    /// Cap on the number of entries in the cache directory. Enforced by `--gc` and
+    /// opportunistically after each cache write.
+    #[arg(long)]
+    max_entries: Option<u64>,
+
     /// Target cli command to cache.
     /// This argument should be quoted if it contains spaces.
     /// For example, 'sleep 10 && date'
-    command: String,
+    #[arg(required_unless_present = "gc")]
+    command: Option<String>,
 }
 
 fn main() {
     let args = Args::parse();
-    let ttl = args.ttl;
-    let command: String = args.command;
-    let does_force_renew_cache = args.force_renew_cache;
 
     let cache_root_dir = env::temp_dir().join("fclicache/caches");
     if !cache_root_dir.exists() {
@@ -37,13 +75,48 @@ fn main() {
         });
     }
 
-    print!(
-        "{}",
-        cache_aware_execute_command(
+    if args.gc {
+        gc(&cache_root_dir, args.max_size, args.max_entries);
+        return;
+    }
+
+    let ttl = args.ttl;
+    let command = args.command.expect("command is required unless --gc is set");
+    let does_force_renew_cache = args.force_renew_cache;
+
+    let cwd = args.cwd.then(|| env::current_dir().expect("Unable to read current directory"));
+    let cache_key = CacheKey::new(&command, cwd, &args.env_vars);
+    let cache_file = cache_root_dir.join(hash(&cache_key));
+
+    if args.warm {
+        warm_cache(
             &command,
+            &cache_file,
+            args.compress,
+            args.cwd,
+            &args.env_vars,
+        );
+        gc(&cache_root_dir, args.max_size, args.max_entries);
+        return;
+    }
+
+    let result = cache_aware_execute_command(
+        &command,
+        &cache_file,
+        &ExecuteOptions {
             ttl,
-            &cache_root_dir.join(hash(&command).to_string()),
             does_force_renew_cache,
-        )
+            stale: args.stale,
+            scope_cwd: args.cwd,
+            scope_env_vars: &args.env_vars,
+            compress: args.compress,
+            max_size: args.max_size,
+            max_entries: args.max_entries,
+        },
     );
+    gc(&cache_root_dir, args.max_size, args.max_entries);
+
+    print!("{}", result.stdout);
+    eprint!("{}", result.stderr);
+    std::process::exit(result.exit_code);
 }